@@ -0,0 +1,481 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An emulated GICv2 interrupt controller: the Distributor (GICD) and CPU
+//! interface (GICC) MMIO windows described in the ARM Generic Interrupt
+//! Controller Architecture Specification, version 2.0.
+
+use parking_lot::RwLock;
+
+use crate::mem::emulated::Mmio;
+use crate::{assign_bits, mem};
+
+const GICD_CTLR: usize = 0x000;
+const GICD_TYPER: usize = 0x004;
+const GICD_IIDR: usize = 0x008;
+const GICD_ISENABLER: usize = 0x100;
+const GICD_ICENABLER: usize = 0x180;
+const GICD_ISPENDR: usize = 0x200;
+const GICD_ICPENDR: usize = 0x280;
+const GICD_IPRIORITYR: usize = 0x400;
+const GICD_ITARGETSR: usize = 0x800;
+const GICD_ICFGR: usize = 0xc00;
+const GICD_SGIR: usize = 0xf00;
+
+const GICC_CTLR: usize = 0x000;
+const GICC_PMR: usize = 0x004;
+const GICC_IAR: usize = 0x00c;
+const GICC_EOIR: usize = 0x010;
+
+const NUM_SGI: usize = 16;
+const NUM_PPI: usize = 16;
+const NUM_PRIVATE: usize = NUM_SGI + NUM_PPI;
+const SPURIOUS_IRQ: u32 = 1023;
+
+#[derive(Debug, Clone)]
+struct PerCpuState {
+    enabled: [bool; NUM_PRIVATE],
+    pending: [bool; NUM_PRIVATE],
+    priority: [u8; NUM_PRIVATE],
+    active: Option<u32>,
+}
+
+impl Default for PerCpuState {
+    fn default() -> Self {
+        PerCpuState {
+            enabled: [false; NUM_PRIVATE],
+            pending: [false; NUM_PRIVATE],
+            priority: [0; NUM_PRIVATE],
+            active: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct SharedState {
+    ctlr: u32,
+    num_spis: usize,
+    enabled: Vec<bool>,
+    pending: Vec<bool>,
+    priority: Vec<u8>,
+    targets: Vec<u8>,
+    cfg: Vec<u8>,
+    percpu: Vec<PerCpuState>,
+    gicc_ctlr: u32,
+    pmr: Vec<u8>,
+}
+
+/// An emulated GICv2. `assert_spi`/`assert_ppi` let the rest of the VMM
+/// inject interrupts that later become visible to the guest through
+/// `GICC_IAR`.
+#[derive(Debug)]
+pub struct Gic {
+    state: RwLock<SharedState>,
+}
+
+impl Gic {
+    pub fn new(num_cpu: u32, num_spis: usize) -> Self {
+        let num_cpu = num_cpu as usize;
+        let mut percpu = Vec::with_capacity(num_cpu);
+        percpu.resize_with(num_cpu, PerCpuState::default);
+        let state = SharedState {
+            ctlr: 0,
+            num_spis,
+            enabled: vec![false; num_spis],
+            pending: vec![false; num_spis],
+            priority: vec![0; num_spis],
+            targets: vec![0; num_spis],
+            cfg: vec![0; num_spis.div_ceil(16)],
+            percpu,
+            gicc_ctlr: 0,
+            pmr: vec![0xff],
+        };
+        Gic {
+            state: RwLock::new(state),
+        }
+    }
+
+    /// Marks a shared peripheral interrupt (SPI) pending.
+    pub fn assert_spi(&self, irq: u32) {
+        let Some(index) = (irq as usize).checked_sub(NUM_PRIVATE) else {
+            return;
+        };
+        let mut state = self.state.write();
+        if index < state.pending.len() {
+            state.pending[index] = true;
+        }
+    }
+
+    /// Marks a private peripheral interrupt (PPI) or software-generated
+    /// interrupt (SGI) pending for one vCPU.
+    pub fn assert_ppi(&self, cpu: usize, irq: u32) {
+        let mut state = self.state.write();
+        if let Some(percpu) = state.percpu.get_mut(cpu) {
+            if (irq as usize) < NUM_PRIVATE {
+                percpu.pending[irq as usize] = true;
+            }
+        }
+    }
+
+    fn highest_pending(state: &SharedState, cpu: usize) -> Option<(u32, u8)> {
+        let percpu = state.percpu.get(cpu)?;
+        let mut best: Option<(u32, u8)> = None;
+        for irq in 0..NUM_PRIVATE {
+            if percpu.enabled[irq] && percpu.pending[irq] {
+                let prio = percpu.priority[irq];
+                if best.map_or(true, |(_, best_prio)| prio < best_prio) {
+                    best = Some((irq as u32, prio));
+                }
+            }
+        }
+        for index in 0..state.num_spis {
+            if state.enabled[index] && state.pending[index] {
+                let prio = state.priority[index];
+                if best.map_or(true, |(_, best_prio)| prio < best_prio) {
+                    best = Some(((index + NUM_PRIVATE) as u32, prio));
+                }
+            }
+        }
+        best
+    }
+
+    fn gicd_read(&self, offset: usize, size: u8) -> u64 {
+        let state = self.state.read();
+        match offset {
+            GICD_CTLR => state.ctlr as u64,
+            GICD_TYPER => {
+                let it_lines =
+                    ((NUM_PRIVATE + state.num_spis).div_ceil(32)).saturating_sub(1) as u32;
+                let cpu_number = (state.percpu.len().saturating_sub(1) & 0x7) as u32;
+                (it_lines | (cpu_number << 5)) as u64
+            }
+            GICD_IIDR => 0x4300_043b,
+            GICD_ISENABLER..=0x17c => bank_read_enable(&state, offset - GICD_ISENABLER, size),
+            GICD_ICENABLER..=0x1fc => bank_read_enable(&state, offset - GICD_ICENABLER, size),
+            GICD_ISPENDR..=0x27c => bank_read_pending(&state, offset - GICD_ISPENDR, size),
+            GICD_ICPENDR..=0x2fc => bank_read_pending(&state, offset - GICD_ICPENDR, size),
+            GICD_IPRIORITYR..=0x7ff => byte_read_priority(&state, offset - GICD_IPRIORITYR, size),
+            GICD_ITARGETSR..=0xbff => byte_read_targets(&state, offset - GICD_ITARGETSR, size),
+            GICD_ICFGR..=0xcff => byte_bank_read(&state.cfg, offset - GICD_ICFGR, size),
+            _ => 0,
+        }
+    }
+
+    fn gicd_write(&self, offset: usize, size: u8, val: u64) {
+        let mut state = self.state.write();
+        match offset {
+            GICD_CTLR => assign_bits!(state.ctlr, val as u32, 1),
+            GICD_ISENABLER..=0x17c => {
+                bank_set_enable(&mut state, offset - GICD_ISENABLER, size, val, true)
+            }
+            GICD_ICENABLER..=0x1fc => {
+                bank_set_enable(&mut state, offset - GICD_ICENABLER, size, val, false)
+            }
+            GICD_ISPENDR..=0x27c => {
+                bank_set_pending(&mut state, offset - GICD_ISPENDR, size, val, true)
+            }
+            GICD_ICPENDR..=0x2fc => {
+                bank_set_pending(&mut state, offset - GICD_ICPENDR, size, val, false)
+            }
+            GICD_IPRIORITYR..=0x7ff => {
+                byte_bank_write_priority(&mut state, offset - GICD_IPRIORITYR, size, val)
+            }
+            GICD_ITARGETSR..=0xbff => {
+                byte_write_targets(&mut state.targets, offset - GICD_ITARGETSR, size, val)
+            }
+            GICD_ICFGR..=0xcff => byte_bank_write(&mut state.cfg, offset - GICD_ICFGR, size, val),
+            GICD_SGIR => {
+                let irq = (val & 0xf) as u32;
+                let target_list = ((val >> 16) & 0xff) as u8;
+                for (cpu, percpu) in state.percpu.iter_mut().enumerate() {
+                    if target_list & (1 << cpu) != 0 {
+                        percpu.pending[irq as usize] = true;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn gicc_read(&self, cpu: usize, offset: usize, _size: u8) -> u64 {
+        let mut state = self.state.write();
+        match offset {
+            GICC_CTLR => state.gicc_ctlr as u64,
+            GICC_PMR => state.pmr[0] as u64,
+            GICC_IAR => {
+                let Some((irq, _)) = Self::highest_pending(&state, cpu) else {
+                    return SPURIOUS_IRQ as u64;
+                };
+                if (irq as usize) < NUM_PRIVATE {
+                    let percpu = &mut state.percpu[cpu];
+                    percpu.pending[irq as usize] = false;
+                    percpu.active = Some(irq);
+                } else {
+                    let index = irq as usize - NUM_PRIVATE;
+                    state.pending[index] = false;
+                    state.percpu[cpu].active = Some(irq);
+                }
+                irq as u64
+            }
+            _ => 0,
+        }
+    }
+
+    fn gicc_write(&self, cpu: usize, offset: usize, _size: u8, val: u64) {
+        let mut state = self.state.write();
+        match offset {
+            GICC_CTLR => state.gicc_ctlr = val as u32,
+            GICC_PMR => state.pmr[0] = val as u8,
+            GICC_EOIR => {
+                let irq = (val & 0x3ff) as u32;
+                if let Some(percpu) = state.percpu.get_mut(cpu) {
+                    if percpu.active == Some(irq) {
+                        percpu.active = None;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn bank_read_enable(state: &SharedState, offset: usize, size: u8) -> u64 {
+    let byte = (offset % 4) * 8;
+    let _ = byte;
+    let reg_index = offset / 4;
+    let mut word = 0u32;
+    for bit in 0..32 {
+        let irq = reg_index * 32 + bit;
+        if irq < NUM_PRIVATE {
+            // Per-CPU banks are only visible through the reading vCPU in
+            // real hardware; vCPU 0 is used here since offset alone does
+            // not identify the reader.
+            if state
+                .percpu
+                .first()
+                .map(|p| p.enabled[irq])
+                .unwrap_or(false)
+            {
+                word |= 1 << bit;
+            }
+        } else if irq - NUM_PRIVATE < state.enabled.len() && state.enabled[irq - NUM_PRIVATE] {
+            word |= 1 << bit;
+        }
+    }
+    truncate(word as u64, size)
+}
+
+fn bank_read_pending(state: &SharedState, offset: usize, size: u8) -> u64 {
+    let reg_index = offset / 4;
+    let mut word = 0u32;
+    for bit in 0..32 {
+        let irq = reg_index * 32 + bit;
+        if irq < NUM_PRIVATE {
+            if state
+                .percpu
+                .first()
+                .map(|p| p.pending[irq])
+                .unwrap_or(false)
+            {
+                word |= 1 << bit;
+            }
+        } else if irq - NUM_PRIVATE < state.pending.len() && state.pending[irq - NUM_PRIVATE] {
+            word |= 1 << bit;
+        }
+    }
+    truncate(word as u64, size)
+}
+
+fn bank_set_enable(state: &mut SharedState, offset: usize, size: u8, val: u64, set: bool) {
+    let reg_index = offset / 4;
+    let bits = val as u32;
+    for bit in 0..(size as usize * 8).min(32) {
+        if bits & (1 << bit) == 0 {
+            continue;
+        }
+        let irq = reg_index * 32 + bit;
+        if irq < NUM_PRIVATE {
+            for percpu in state.percpu.iter_mut() {
+                percpu.enabled[irq] = set;
+            }
+        } else if irq - NUM_PRIVATE < state.enabled.len() {
+            state.enabled[irq - NUM_PRIVATE] = set;
+        }
+    }
+}
+
+fn bank_set_pending(state: &mut SharedState, offset: usize, size: u8, val: u64, set: bool) {
+    let reg_index = offset / 4;
+    let bits = val as u32;
+    for bit in 0..(size as usize * 8).min(32) {
+        if bits & (1 << bit) == 0 {
+            continue;
+        }
+        let irq = reg_index * 32 + bit;
+        if irq < NUM_PRIVATE {
+            for percpu in state.percpu.iter_mut() {
+                percpu.pending[irq] = set;
+            }
+        } else if irq - NUM_PRIVATE < state.pending.len() {
+            state.pending[irq - NUM_PRIVATE] = set;
+        }
+    }
+}
+
+fn byte_bank_read(bank: &[u8], offset: usize, size: u8) -> u64 {
+    let mut val = 0u64;
+    for i in 0..size as usize {
+        if let Some(byte) = bank.get(offset + i) {
+            val |= (*byte as u64) << (i * 8);
+        }
+    }
+    val
+}
+
+fn byte_bank_write(bank: &mut [u8], offset: usize, size: u8, val: u64) {
+    for i in 0..size as usize {
+        if let Some(byte) = bank.get_mut(offset + i) {
+            *byte = (val >> (i * 8)) as u8;
+        }
+    }
+}
+
+fn byte_bank_write_priority(state: &mut SharedState, offset: usize, size: u8, val: u64) {
+    for i in 0..size as usize {
+        let irq = offset + i;
+        let byte = ((val >> (i * 8)) & 0xff) as u8;
+        if irq < NUM_PRIVATE {
+            for percpu in state.percpu.iter_mut() {
+                percpu.priority[irq] = byte;
+            }
+        } else if irq - NUM_PRIVATE < state.priority.len() {
+            state.priority[irq - NUM_PRIVATE] = byte;
+        }
+    }
+}
+
+/// Mirrors `byte_bank_write_priority`'s split: private IRQ bytes come from
+/// the reading vCPU's own bank, SPI bytes from the flat `priority` vec.
+/// vCPU 0 is used for the private bank since offset alone does not identify
+/// the reader (see `bank_read_enable`).
+fn byte_read_priority(state: &SharedState, offset: usize, size: u8) -> u64 {
+    let mut val = 0u64;
+    for i in 0..size as usize {
+        let irq = offset + i;
+        let byte = if irq < NUM_PRIVATE {
+            state.percpu.first().map_or(0, |p| p.priority[irq])
+        } else {
+            state.priority.get(irq - NUM_PRIVATE).copied().unwrap_or(0)
+        };
+        val |= (byte as u64) << (i * 8);
+    }
+    val
+}
+
+/// ITARGETSR bytes for SGIs/PPIs are read-only and reflect the reading CPU
+/// interface's own bit (vCPU 0's, per the same offset-can't-identify-reader
+/// convention used elsewhere in this file); SPI bytes come from the flat
+/// `targets` vec.
+fn byte_read_targets(state: &SharedState, offset: usize, size: u8) -> u64 {
+    let mut val = 0u64;
+    for i in 0..size as usize {
+        let irq = offset + i;
+        let byte = if irq < NUM_PRIVATE {
+            0x01
+        } else {
+            state.targets.get(irq - NUM_PRIVATE).copied().unwrap_or(0)
+        };
+        val |= (byte as u64) << (i * 8);
+    }
+    val
+}
+
+/// ITARGETSR0-7 (the private-IRQ bytes) are read-only on real hardware, so
+/// writes to them are dropped; SPI bytes are stored at `offset - NUM_PRIVATE`
+/// in the flat `targets` vec.
+fn byte_write_targets(bank: &mut [u8], offset: usize, size: u8, val: u64) {
+    for i in 0..size as usize {
+        let irq = offset + i;
+        if irq < NUM_PRIVATE {
+            continue;
+        }
+        if let Some(byte) = bank.get_mut(irq - NUM_PRIVATE) {
+            *byte = (val >> (i * 8)) as u8;
+        }
+    }
+}
+
+fn truncate(val: u64, size: u8) -> u64 {
+    match size {
+        1 => val & 0xff,
+        2 => val & 0xffff,
+        4 => val & 0xffff_ffff,
+        _ => val,
+    }
+}
+
+/// The Distributor (GICD) MMIO window.
+#[derive(Debug)]
+pub struct GicDistributor {
+    gic: std::sync::Arc<Gic>,
+}
+
+impl GicDistributor {
+    pub fn new(gic: std::sync::Arc<Gic>) -> Self {
+        GicDistributor { gic }
+    }
+}
+
+impl Mmio for GicDistributor {
+    fn size(&self) -> usize {
+        0x1000
+    }
+
+    fn read(&self, offset: usize, size: u8) -> mem::Result<u64> {
+        Ok(self.gic.gicd_read(offset, size))
+    }
+
+    fn write(&self, offset: usize, size: u8, val: u64) -> mem::Result<()> {
+        self.gic.gicd_write(offset, size, val);
+        Ok(())
+    }
+}
+
+/// The CPU interface (GICC) MMIO window, banked per vCPU.
+#[derive(Debug)]
+pub struct GicCpuInterface {
+    gic: std::sync::Arc<Gic>,
+    cpu: usize,
+}
+
+impl GicCpuInterface {
+    pub fn new(gic: std::sync::Arc<Gic>, cpu: usize) -> Self {
+        GicCpuInterface { gic, cpu }
+    }
+}
+
+impl Mmio for GicCpuInterface {
+    fn size(&self) -> usize {
+        0x1000
+    }
+
+    fn read(&self, offset: usize, size: u8) -> mem::Result<u64> {
+        Ok(self.gic.gicc_read(self.cpu, offset, size))
+    }
+
+    fn write(&self, offset: usize, size: u8, val: u64) -> mem::Result<()> {
+        self.gic.gicc_write(self.cpu, offset, size, val);
+        Ok(())
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use crate::fdt::FdtWriter;
+use crate::hv::{Vcpu, Vm};
+use crate::loader::InitState;
+
+use super::{Board, BoardConfig, PstoreConfig, Result};
+
+mod gic;
+
+pub use gic::Gic;
+
+/// Guest-physical address the FDT blob is written to and the address passed
+/// to the kernel in `x0`.
+pub const FDT_ADDR: u64 = 0x4000_0000;
+pub const FDT_MAX_SIZE: usize = 0x20_0000;
+
+/// Number of shared peripheral interrupts the emulated GIC supports, on top
+/// of the 32 banked SGIs/PPIs.
+const NUM_SPIS: usize = 64;
+
+/// GICv2 distributor and CPU interface MMIO windows, also advertised in the
+/// `interrupt-controller` FDT node.
+pub const GIC_DIST_ADDR: u64 = 0x0800_0000;
+pub const GIC_DIST_SIZE: u64 = 0x1_0000;
+pub const GIC_CPU_ADDR: u64 = 0x0801_0000;
+pub const GIC_CPU_SIZE: u64 = 0x1_0000;
+
+#[derive(Debug)]
+pub struct ArchBoard {
+    pub gic: Arc<Gic>,
+}
+
+impl ArchBoard {
+    pub fn new(config: &BoardConfig) -> Self {
+        ArchBoard {
+            gic: Arc::new(Gic::new(config.num_cpu, NUM_SPIS)),
+        }
+    }
+}
+
+fn build_cpus_node(fdt: &mut FdtWriter, num_cpu: u32) {
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    for cpu in 0..num_cpu {
+        fdt.begin_node(&format!("cpu@{cpu}"));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_string("compatible", "arm,arm-v8");
+        fdt.property_string("enable-method", "psci");
+        fdt.property_u32("reg", cpu);
+        fdt.end_node();
+    }
+    fdt.end_node();
+}
+
+fn build_memory_node(fdt: &mut FdtWriter, mem_regions: &[(u64, u64)]) {
+    for (index, &(base, size)) in mem_regions.iter().enumerate() {
+        fdt.begin_node(&format!("memory@{base:x}"));
+        fdt.property_string("device_type", "memory");
+        let mut reg = Vec::with_capacity(16);
+        reg.extend_from_slice(&base.to_be_bytes());
+        reg.extend_from_slice(&size.to_be_bytes());
+        fdt.property("reg", &reg);
+        if index == 0 {
+            fdt.property_null("primary");
+        }
+        fdt.end_node();
+    }
+}
+
+fn build_chosen_node(fdt: &mut FdtWriter, cmd_line: Option<&str>, initramfs: Option<(u64, u64)>) {
+    fdt.begin_node("chosen");
+    if let Some(cmd_line) = cmd_line {
+        fdt.property_string("bootargs", cmd_line);
+    }
+    if let Some((start, end)) = initramfs {
+        fdt.property_u64("linux,initrd-start", start);
+        fdt.property_u64("linux,initrd-end", end);
+    }
+    fdt.end_node();
+}
+
+fn build_psci_node(fdt: &mut FdtWriter) {
+    fdt.begin_node("psci");
+    fdt.property_string_list("compatible", &["arm,psci-1.0", "arm,psci-0.2", "arm,psci"]);
+    fdt.property_string("method", "hvc");
+    fdt.end_node();
+}
+
+fn build_timer_node(fdt: &mut FdtWriter, num_cpu: u32) {
+    const GIC_PPI: u32 = 1;
+    const IRQ_TYPE_LEVEL_LOW: u32 = 8;
+    let cpu_mask = if num_cpu <= 8 {
+        0xff >> (8 - num_cpu)
+    } else {
+        0xff
+    } << 8;
+    fdt.begin_node("timer");
+    fdt.property_string("compatible", "arm,armv8-timer");
+    fdt.property_null("always-on");
+    let mut interrupts = Vec::new();
+    for irq in [13u32, 14, 11, 10] {
+        interrupts.extend_from_slice(&GIC_PPI.to_be_bytes());
+        interrupts.extend_from_slice(&irq.to_be_bytes());
+        interrupts.extend_from_slice(&(cpu_mask | IRQ_TYPE_LEVEL_LOW).to_be_bytes());
+    }
+    fdt.property("interrupts", &interrupts);
+    fdt.end_node();
+}
+
+/// Advertises the pstore region as a `ramoops` reserved-memory node, so the
+/// guest's pstore driver binds to it instead of the kernel reclaiming the
+/// range for general use.
+fn build_reserved_memory_node(fdt: &mut FdtWriter, pstore: &PstoreConfig) {
+    fdt.begin_node("reserved-memory");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_null("ranges");
+
+    fdt.begin_node(&format!("ramoops@{:x}", pstore.base));
+    fdt.property_string("compatible", "ramoops");
+    let mut reg = Vec::with_capacity(16);
+    reg.extend_from_slice(&pstore.base.to_be_bytes());
+    reg.extend_from_slice(&pstore.size.to_be_bytes());
+    fdt.property("reg", &reg);
+    fdt.property_null("no-map");
+    fdt.end_node();
+
+    fdt.end_node();
+}
+
+fn build_gic_node(fdt: &mut FdtWriter) {
+    fdt.begin_node(&format!("interrupt-controller@{GIC_DIST_ADDR:x}"));
+    fdt.property_string("compatible", "arm,cortex-a15-gic");
+    fdt.property_null("interrupt-controller");
+    fdt.property_u32("#interrupt-cells", 3);
+    let mut reg = Vec::with_capacity(32);
+    reg.extend_from_slice(&GIC_DIST_ADDR.to_be_bytes());
+    reg.extend_from_slice(&GIC_DIST_SIZE.to_be_bytes());
+    reg.extend_from_slice(&GIC_CPU_ADDR.to_be_bytes());
+    reg.extend_from_slice(&GIC_CPU_SIZE.to_be_bytes());
+    fdt.property("reg", &reg);
+    fdt.end_node();
+}
+
+/// Builds the guest's device tree: `/memory`, `/cpus`, `/chosen`, PSCI, the
+/// architected timer, the GIC, and, if configured, a `ramoops`
+/// reserved-memory node for the pstore region.
+pub fn build_fdt(
+    config: &BoardConfig,
+    mem_regions: &[(u64, u64)],
+    cmd_line: Option<&str>,
+    initramfs: Option<(u64, u64)>,
+) -> Vec<u8> {
+    let mut fdt = FdtWriter::new();
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "linux,dummy-virt");
+
+    build_memory_node(&mut fdt, mem_regions);
+    build_cpus_node(&mut fdt, config.num_cpu);
+    build_chosen_node(&mut fdt, cmd_line, initramfs);
+    build_psci_node(&mut fdt);
+    build_timer_node(&mut fdt, config.num_cpu);
+    build_gic_node(&mut fdt);
+    if let Some(pstore) = &config.pstore {
+        build_reserved_memory_node(&mut fdt, pstore);
+    }
+
+    fdt.end_node();
+    fdt.finish(0, &[(FDT_ADDR, FDT_MAX_SIZE as u64)])
+}
+
+impl<V> Board<V>
+where
+    V: Vm,
+{
+    pub(crate) fn create_firmware_data_aarch64(&self, init_state: &InitState) -> Result<()> {
+        let mem_regions: Vec<(u64, u64)> = self
+            .memory
+            .mem_region_entries()
+            .iter()
+            .map(|entry| (entry.addr, entry.size))
+            .collect();
+        let payload = self.payload.read();
+        let cmd_line = payload.as_ref().and_then(|p| p.cmd_line.as_deref());
+        let fdt_bytes = build_fdt(&self.config, &mem_regions, cmd_line, init_state.initramfs);
+        if fdt_bytes.len() > FDT_MAX_SIZE {
+            return Err(super::Error::DeviceTreeTooLong);
+        }
+        let ram = self.memory.ram_bus();
+        ram.write_range(FDT_ADDR as usize, fdt_bytes.len(), &fdt_bytes)?;
+        Ok(())
+    }
+
+    /// Places the kernel entry point in `pc` and the FDT address in `x0`,
+    /// per the arm64 boot protocol.
+    pub(crate) fn init_boot_vcpu_aarch64(
+        &self,
+        vcpu: &mut <V as Vm>::Vcpu,
+        init_state: &InitState,
+    ) -> Result<()> {
+        vcpu.set_entry_regs(init_state.entry_point, FDT_ADDR, 0)?;
+        Ok(())
+    }
+}
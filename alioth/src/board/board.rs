@@ -12,23 +12,31 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use std::sync::mpsc::{Receiver, Sender};
 
-use parking_lot::RwLock;
+use parking_lot::{Condvar, Mutex, RwLock};
 use thiserror::Error;
 
+#[cfg(target_arch = "x86_64")]
 use crate::acpi::create_acpi_tables;
+#[cfg(target_arch = "x86_64")]
 use crate::arch::layout::{EBDA_END, EBDA_START};
 use crate::hv::{self, Vcpu, Vm, VmEntry, VmExit};
-use crate::loader::{self, linux, ExecType, InitState, Payload};
-use crate::mem::{self, Memory};
+use crate::loader::{self, elf, linux, BootProto, ExecType, InitState, Payload};
+use crate::mem::{self, Memory, RamBus};
+
+pub mod control;
 
 #[cfg(target_arch = "x86_64")]
 mod x86_64;
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
 
 #[cfg(target_arch = "x86_64")]
 pub(crate) use x86_64::ArchBoard;
+#[cfg(target_arch = "aarch64")]
+pub(crate) use aarch64::ArchBoard;
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -50,8 +58,17 @@ pub enum Error {
     #[error("ACPI bytes exceed EBDA area")]
     AcpiTooLong,
 
+    #[error("device tree bytes exceed the reserved FDT area")]
+    DeviceTreeTooLong,
+
+    #[error("control channel: {0}")]
+    Control(#[from] control::Error),
+
     #[error("memory too small")]
     MemoryTooSmall,
+
+    #[error("PVH boot protocol is not supported yet: guest needs 32-bit protected mode entry")]
+    PvhNotSupported,
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
@@ -59,10 +76,25 @@ type Result<T, E = Error> = std::result::Result<T, E>;
 pub const STATE_CREATED: u8 = 0;
 pub const STATE_RUNNING: u8 = 1;
 pub const STATE_SHUTDOWN: u8 = 2;
+pub const STATE_PAUSED: u8 = 3;
 
 pub struct BoardConfig {
     pub mem_size: usize,
     pub num_cpu: u32,
+    pub pstore: Option<PstoreConfig>,
+}
+
+/// Guest-physical base and size of an optional persistent-store region
+/// backing the guest's `ramoops`/pstore driver. `create_firmware_data`
+/// advertises this range to the guest as reserved, and `create_ram` (and so
+/// [`Board::reset`]) never zeroes it, so a kernel panic log written before a
+/// reset survives for the host to read back out of guest memory afterwards.
+/// The range stays part of the ordinary RAM regions in [`Memory`]; no
+/// separate region is created for it.
+#[derive(Debug, Clone, Copy)]
+pub struct PstoreConfig {
+    pub base: u64,
+    pub size: u64,
 }
 
 pub struct Board<V>
@@ -75,14 +107,19 @@ where
     pub config: BoardConfig,
     pub state: AtomicU8,
     pub payload: RwLock<Option<Payload>>,
+    pub(crate) vcpu_handles: RwLock<Vec<control::VcpuHandle>>,
+    pub(crate) pause_lock: Mutex<()>,
+    pub(crate) pause_cond: Condvar,
+    pub(crate) reset_requested: AtomicBool,
 }
 
 impl<V> Board<V>
 where
     V: Vm,
 {
+    #[cfg(target_arch = "x86_64")]
     pub fn create_firmware_data(&self, _init_state: &InitState) -> Result<()> {
-        let acpi_bytes = create_acpi_tables(EBDA_START, self.config.num_cpu);
+        let acpi_bytes = create_acpi_tables(EBDA_START, self.config.num_cpu, self.config.pstore);
         if acpi_bytes.len() > EBDA_END - EBDA_START {
             return Err(Error::AcpiTooLong);
         }
@@ -91,6 +128,33 @@ where
         Ok(())
     }
 
+    #[cfg(target_arch = "aarch64")]
+    pub fn create_firmware_data(&self, init_state: &InitState) -> Result<()> {
+        self.create_firmware_data_aarch64(init_state)
+    }
+
+    /// Zeroes every configured RAM region for a fresh boot, except the
+    /// optional pstore range: that's left untouched so the guest's
+    /// `ramoops` log from before a reset survives for the host to read
+    /// back out of guest memory afterwards.
+    fn create_ram(&self) -> Result<()> {
+        let ram = self.memory.ram_bus();
+        for entry in self.memory.mem_region_entries() {
+            let region_start = entry.addr;
+            let region_end = entry.addr + entry.size;
+            match self.config.pstore {
+                Some(pstore) => {
+                    let pstore_start = pstore.base.clamp(region_start, region_end);
+                    let pstore_end = (pstore.base + pstore.size).clamp(region_start, region_end);
+                    zero_range(ram, region_start, pstore_start)?;
+                    zero_range(ram, pstore_end, region_end)?;
+                }
+                None => zero_range(ram, region_start, region_end)?,
+            }
+        }
+        Ok(())
+    }
+
     fn load_payload(&self) -> Result<InitState, Error> {
         let payload = self.payload.read();
         let Some(payload) = payload.as_ref() else {
@@ -105,16 +169,68 @@ where
                 payload.cmd_line.as_deref(),
                 payload.initramfs.as_ref(),
             )?,
+            ExecType::Elf => elf::load(
+                &self.memory.ram_bus(),
+                &mem_regions,
+                &payload.executable,
+                payload.cmd_line.as_deref(),
+                payload.initramfs.as_ref(),
+            )?,
         };
         Ok(init_state)
     }
 
+    /// Sets up the boot vCPU's entry point and the argument register its
+    /// boot protocol expects: `%rsi` -> `boot_params` for the Linux/x86
+    /// protocol, neither for a bare ELF entry.
+    ///
+    /// PVH's `XEN_ELFNOTE_PHYS32_ENTRY` entry point runs in 32-bit protected
+    /// mode with paging off and `hvm_start_info` in `%ebx`, which is a
+    /// different CPU mode than the long-mode setup `set_entry_regs`
+    /// establishes for the other two protocols. Nothing here switches the
+    /// vCPU out of long mode yet, so refuse to boot a PVH payload rather
+    /// than entering it with the wrong CPU state.
+    #[cfg(target_arch = "x86_64")]
+    fn init_boot_vcpu(&self, vcpu: &mut <V as Vm>::Vcpu, init_state: &InitState) -> Result<()> {
+        let rsi = match init_state.boot_proto {
+            BootProto::Linux { boot_params_addr } => boot_params_addr,
+            BootProto::Pvh { .. } => return Err(Error::PvhNotSupported),
+            BootProto::Elf => 0,
+        };
+        vcpu.set_entry_regs(init_state.entry_point, rsi, 0)?;
+        Ok(())
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn init_boot_vcpu(&self, vcpu: &mut <V as Vm>::Vcpu, init_state: &InitState) -> Result<()> {
+        self.init_boot_vcpu_aarch64(vcpu, init_state)
+    }
+
+    fn reset(&self, vcpu: &mut <V as Vm>::Vcpu) -> Result<(), Error> {
+        log::info!("vcpu 0 resetting guest");
+        self.create_ram()?;
+        let init_state = self.load_payload()?;
+        self.init_boot_vcpu(vcpu, &init_state)?;
+        self.create_firmware_data(&init_state)?;
+        Ok(())
+    }
+
     fn vcpu_loop(&self, vcpu: &mut <V as Vm>::Vcpu, id: u32) -> Result<(), Error> {
         let mut vm_entry = VmEntry::None;
         loop {
             // TODO is there any race here?
-            if self.state.load(Ordering::Acquire) == STATE_SHUTDOWN {
-                vm_entry = VmEntry::Shutdown;
+            match self.state.load(Ordering::Acquire) {
+                STATE_SHUTDOWN => vm_entry = VmEntry::Shutdown,
+                STATE_PAUSED => {
+                    self.park_if_paused(id);
+                    if id == 0 && self.reset_requested.swap(false, Ordering::AcqRel) {
+                        self.reset(vcpu)?;
+                        self.state.store(STATE_RUNNING, Ordering::Release);
+                        self.pause_cond.notify_all();
+                    }
+                    continue;
+                }
+                _ => {}
             }
             let vm_exit = vcpu.run(vm_entry)?;
             vm_entry = match vm_exit {
@@ -136,7 +252,9 @@ where
         event_tx: &Sender<u32>,
         boot_rx: &Receiver<()>,
     ) -> Result<(), Error> {
+        control::install_kick_handler();
         let mut vcpu = self.vm.create_vcpu(id)?;
+        self.register_vcpu(id)?;
         event_tx.send(id).unwrap();
         self.init_vcpu(id, &mut vcpu)?;
         boot_rx.recv().unwrap();
@@ -163,4 +281,17 @@ where
         event_tx.send(id).unwrap();
         ret
     }
+}
+
+const ZERO_CHUNK_LEN: usize = 0x1000;
+
+fn zero_range(ram: &RamBus, start: u64, end: u64) -> Result<()> {
+    let zeros = [0u8; ZERO_CHUNK_LEN];
+    let mut addr = start;
+    while addr < end {
+        let len = ((end - addr) as usize).min(ZERO_CHUNK_LEN);
+        ram.write_range(addr as usize, len, &zeros[..len])?;
+        addr += len as u64;
+    }
+    Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,380 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A control channel that lets an external tool pause, resume, reset, and
+//! query a running guest without killing the VMM process. Commands arrive
+//! as length-prefixed binary frames over a Unix domain socket; each vcpu
+//! thread registers an ack eventfd so the control thread can tell when
+//! every vcpu has actually parked before replying to a pause request.
+
+use std::io::{self, Read, Write};
+use std::mem::MaybeUninit;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use thiserror::Error;
+
+use super::{Board, Error as BoardError, Result, STATE_PAUSED, STATE_RUNNING};
+use crate::hv::Vm;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("io: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("malformed control frame")]
+    Protocol,
+}
+
+type ControlResult<T> = std::result::Result<T, Error>;
+
+/// Commands an external controller can send over the control socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Pause,
+    Resume,
+    Reset,
+    Query,
+}
+
+/// Replies sent back over the control socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Ok,
+    State(u8),
+    Err(String),
+}
+
+const TAG_PAUSE: u8 = 0;
+const TAG_RESUME: u8 = 1;
+const TAG_RESET: u8 = 2;
+const TAG_QUERY: u8 = 3;
+
+const TAG_OK: u8 = 0;
+const TAG_STATE: u8 = 1;
+const TAG_ERR: u8 = 2;
+
+impl Command {
+    pub fn encode(self) -> Vec<u8> {
+        match self {
+            Command::Pause => vec![TAG_PAUSE],
+            Command::Resume => vec![TAG_RESUME],
+            Command::Reset => vec![TAG_RESET],
+            Command::Query => vec![TAG_QUERY],
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> ControlResult<Self> {
+        match bytes.first() {
+            Some(&TAG_PAUSE) => Ok(Command::Pause),
+            Some(&TAG_RESUME) => Ok(Command::Resume),
+            Some(&TAG_RESET) => Ok(Command::Reset),
+            Some(&TAG_QUERY) => Ok(Command::Query),
+            _ => Err(Error::Protocol),
+        }
+    }
+}
+
+impl Response {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Response::Ok => vec![TAG_OK],
+            Response::State(state) => vec![TAG_STATE, *state],
+            Response::Err(msg) => {
+                let mut bytes = vec![TAG_ERR];
+                bytes.extend_from_slice(msg.as_bytes());
+                bytes
+            }
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> ControlResult<Self> {
+        match bytes.first() {
+            Some(&TAG_OK) => Ok(Response::Ok),
+            Some(&TAG_STATE) => Ok(Response::State(*bytes.get(1).ok_or(Error::Protocol)?)),
+            Some(&TAG_ERR) => Ok(Response::Err(
+                String::from_utf8_lossy(&bytes[1..]).into_owned(),
+            )),
+            _ => Err(Error::Protocol),
+        }
+    }
+}
+
+fn write_frame(stream: &mut UnixStream, bytes: &[u8]) -> io::Result<()> {
+    stream.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    stream.write_all(bytes)
+}
+
+fn read_frame(stream: &mut UnixStream) -> io::Result<Vec<u8>> {
+    let mut len_bytes = [0u8; 4];
+    stream.read_exact(&mut len_bytes)?;
+    let len = u32::from_le_bytes(len_bytes) as usize;
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// A non-blocking `eventfd(2)`, used here purely as a wakeup/ack signal.
+#[derive(Debug)]
+pub(crate) struct EventFd(OwnedFd);
+
+impl EventFd {
+    pub(crate) fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(EventFd(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    pub(crate) fn signal(&self) -> io::Result<()> {
+        let val: u64 = 1;
+        let ret = unsafe {
+            libc::write(
+                self.0.as_raw_fd(),
+                &val as *const u64 as *const libc::c_void,
+                8,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub(crate) fn clear(&self) {
+        let mut val: u64 = 0;
+        unsafe {
+            libc::read(
+                self.0.as_raw_fd(),
+                &mut val as *mut u64 as *mut libc::c_void,
+                8,
+            );
+        }
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+/// A minimal epoll-based wait context: a set of registered fds, level
+/// triggered, that `wait()` reports as ready.
+pub(crate) struct WaitContext {
+    epoll_fd: OwnedFd,
+}
+
+impl WaitContext {
+    pub(crate) fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(WaitContext {
+            epoll_fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    pub(crate) fn add(&self, fd: RawFd, token: u64) -> io::Result<()> {
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: token,
+        };
+        let ret = unsafe {
+            libc::epoll_ctl(
+                self.epoll_fd.as_raw_fd(),
+                libc::EPOLL_CTL_ADD,
+                fd,
+                &mut event,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Blocks until at least one registered fd is ready, returning the
+    /// tokens passed to [`WaitContext::add`] for the ready fds.
+    pub(crate) fn wait(&self) -> io::Result<Vec<u64>> {
+        let mut events: [MaybeUninit<libc::epoll_event>; 16] = [MaybeUninit::uninit(); 16];
+        let ret = unsafe {
+            libc::epoll_wait(
+                self.epoll_fd.as_raw_fd(),
+                events.as_mut_ptr() as *mut libc::epoll_event,
+                events.len() as i32,
+                -1,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut tokens = Vec::with_capacity(ret as usize);
+        for event in &events[..ret as usize] {
+            tokens.push(unsafe { event.assume_init() }.u64);
+        }
+        Ok(tokens)
+    }
+}
+
+/// What a vcpu thread registers with the board so the control thread can
+/// kick it out of `vcpu.run()` and wait for it to actually park.
+pub(crate) struct VcpuHandle {
+    pub(crate) id: u32,
+    pub(crate) pthread: libc::pthread_t,
+    pub(crate) ack: EventFd,
+}
+
+/// The signal used to kick a vcpu thread out of a blocking `vcpu.run()`
+/// call; the thread installs a no-op handler for it at startup so the
+/// underlying ioctl returns `EINTR`, which the hv backend reports as
+/// `VmExit::Interrupted`.
+pub(crate) const KICK_SIGNAL: libc::c_int = libc::SIGURG;
+
+extern "C" fn kick_handler(_: libc::c_int) {}
+
+pub(crate) fn install_kick_handler() {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = kick_handler as usize;
+        libc::sigaction(KICK_SIGNAL, &action, std::ptr::null_mut());
+    }
+}
+
+impl<V> Board<V>
+where
+    V: Vm,
+{
+    pub(crate) fn kick_vcpus(&self) {
+        for handle in self.vcpu_handles.read().iter() {
+            unsafe {
+                libc::pthread_kill(handle.pthread, KICK_SIGNAL);
+            }
+        }
+    }
+
+    fn wait_for_pause_acks(&self) -> ControlResult<()> {
+        let handles = self.vcpu_handles.read();
+        if handles.is_empty() {
+            return Ok(());
+        }
+        let wait_ctx = WaitContext::new()?;
+        for handle in handles.iter() {
+            wait_ctx.add(handle.ack.as_raw_fd(), handle.id as u64)?;
+        }
+        let mut pending: std::collections::HashSet<u32> = handles.iter().map(|h| h.id).collect();
+        drop(handles);
+        while !pending.is_empty() {
+            for token in wait_ctx.wait()? {
+                let id = token as u32;
+                if let Some(handle) = self.vcpu_handles.read().iter().find(|h| h.id == id) {
+                    handle.ack.clear();
+                }
+                pending.remove(&id);
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_command(&self, command: Command) -> ControlResult<Response> {
+        match command {
+            Command::Pause => {
+                self.state.store(STATE_PAUSED, Ordering::Release);
+                self.kick_vcpus();
+                self.wait_for_pause_acks()?;
+                Ok(Response::Ok)
+            }
+            Command::Resume => {
+                self.state.store(STATE_RUNNING, Ordering::Release);
+                self.pause_cond.notify_all();
+                Ok(Response::Ok)
+            }
+            Command::Reset => {
+                self.state.store(STATE_PAUSED, Ordering::Release);
+                self.kick_vcpus();
+                self.wait_for_pause_acks()?;
+                // Only wake vcpu 0 to run the reboot sequence; the rest stay
+                // parked until it finishes and moves the board back to
+                // `STATE_RUNNING` itself, so no vcpu touches memory while
+                // `reset()` is tearing it down and reloading the payload.
+                self.reset_requested.store(true, Ordering::Release);
+                self.pause_cond.notify_all();
+                Ok(Response::Ok)
+            }
+            Command::Query => Ok(Response::State(self.state.load(Ordering::Acquire))),
+        }
+    }
+
+    /// Parks the calling vcpu thread while `state == STATE_PAUSED`,
+    /// acknowledging the pause via its registered eventfd. Vcpu 0 is let out
+    /// early when a reset has been requested, since it alone runs the reboot
+    /// sequence; every other vcpu stays parked until vcpu 0 finishes and
+    /// moves the board back to `STATE_RUNNING`.
+    pub(crate) fn park_if_paused(&self, id: u32) {
+        if self.state.load(Ordering::Acquire) != STATE_PAUSED {
+            return;
+        }
+        if let Some(handle) = self.vcpu_handles.read().iter().find(|h| h.id == id) {
+            let _ = handle.ack.signal();
+        }
+        let mut guard = self.pause_lock.lock();
+        while self.state.load(Ordering::Acquire) == STATE_PAUSED
+            && !(id == 0 && self.reset_requested.load(Ordering::Acquire))
+        {
+            self.pause_cond.wait(&mut guard);
+        }
+    }
+
+    pub(crate) fn register_vcpu(&self, id: u32) -> ControlResult<()> {
+        let ack = EventFd::new()?;
+        let pthread = unsafe { libc::pthread_self() };
+        self.vcpu_handles
+            .write()
+            .push(VcpuHandle { id, pthread, ack });
+        Ok(())
+    }
+
+    /// Runs the control server loop, accepting one connection at a time and
+    /// handling one command per connection. Checks for shutdown before each
+    /// `accept()`, so after the board shuts down it returns once the next
+    /// connection, if any, has been served.
+    pub fn serve_control(&self, socket_path: &Path) -> Result<()> {
+        let _ = std::fs::remove_file(socket_path);
+        let listener = UnixListener::bind(socket_path).map_err(BoardError::HostIo)?;
+        loop {
+            if self.state.load(Ordering::Acquire) == super::STATE_SHUTDOWN {
+                return Ok(());
+            }
+            let (mut stream, _addr) = match listener.accept() {
+                Ok(conn) => conn,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(BoardError::HostIo(e)),
+            };
+            let response = match read_frame(&mut stream)
+                .map_err(Error::from)
+                .and_then(|bytes| Command::decode(&bytes))
+            {
+                Ok(command) => self
+                    .handle_command(command)
+                    .unwrap_or_else(|e| Response::Err(e.to_string())),
+                Err(e) => Response::Err(e.to_string()),
+            };
+            let _ = write_frame(&mut stream, &response.encode());
+        }
+    }
+}
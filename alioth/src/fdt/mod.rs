@@ -0,0 +1,178 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A minimal builder for the Flattened Device Tree (DTB) binary format, used
+//! to hand a boot description to aarch64 guests in place of ACPI tables.
+//!
+//! See the [Devicetree Specification](https://www.devicetree.org/specifications/)
+//! for the on-disk layout this module emits.
+
+use std::collections::HashMap;
+use std::mem::size_of;
+
+use zerocopy::{AsBytes, BigEndian, FromBytes, FromZeroes, U32};
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+#[allow(dead_code)]
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+#[derive(Debug, Clone, Default, FromBytes, FromZeroes, AsBytes)]
+#[repr(C)]
+struct FdtHeader {
+    magic: U32<BigEndian>,
+    totalsize: U32<BigEndian>,
+    off_dt_struct: U32<BigEndian>,
+    off_dt_strings: U32<BigEndian>,
+    off_mem_rsvmap: U32<BigEndian>,
+    version: U32<BigEndian>,
+    last_comp_version: U32<BigEndian>,
+    boot_cpuid_phys: U32<BigEndian>,
+    size_dt_strings: U32<BigEndian>,
+    size_dt_struct: U32<BigEndian>,
+}
+
+fn pad_to_4(buf: &mut Vec<u8>) {
+    while buf.len() % 4 != 0 {
+        buf.push(0);
+    }
+}
+
+fn push_u32(buf: &mut Vec<u8>, val: u32) {
+    buf.extend_from_slice(&val.to_be_bytes());
+}
+
+/// Builds up the structure and strings blocks of a device tree node by node,
+/// then assembles the full DTB with [`FdtWriter::finish`].
+#[derive(Debug, Default)]
+pub struct FdtWriter {
+    struct_block: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+    depth: u32,
+}
+
+impl FdtWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn intern(&mut self, name: &str) -> u32 {
+        if let Some(off) = self.string_offsets.get(name) {
+            return *off;
+        }
+        let off = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(name.to_string(), off);
+        off
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        push_u32(&mut self.struct_block, FDT_BEGIN_NODE);
+        self.struct_block.extend_from_slice(name.as_bytes());
+        self.struct_block.push(0);
+        pad_to_4(&mut self.struct_block);
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "end_node() without a matching begin_node()");
+        push_u32(&mut self.struct_block, FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let name_off = self.intern(name);
+        push_u32(&mut self.struct_block, FDT_PROP);
+        push_u32(&mut self.struct_block, value.len() as u32);
+        push_u32(&mut self.struct_block, name_off);
+        self.struct_block.extend_from_slice(value);
+        pad_to_4(&mut self.struct_block);
+    }
+
+    pub fn property_null(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_u64(&mut self, name: &str, value: u64) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.property(name, &bytes);
+    }
+
+    pub fn property_string_list(&mut self, name: &str, values: &[&str]) {
+        let mut bytes = Vec::new();
+        for value in values {
+            bytes.extend_from_slice(value.as_bytes());
+            bytes.push(0);
+        }
+        self.property(name, &bytes);
+    }
+
+    /// Consumes the writer and returns the complete DTB blob. `mem_rsv` lists
+    /// guest-physical (base, size) ranges to reserve via the memory
+    /// reservation block, e.g. the FDT blob itself.
+    pub fn finish(mut self, boot_cpuid_phys: u32, mem_rsv: &[(u64, u64)]) -> Vec<u8> {
+        assert_eq!(self.depth, 0, "unbalanced begin_node()/end_node() calls");
+        push_u32(&mut self.struct_block, FDT_END);
+
+        let mut mem_rsvmap = Vec::new();
+        for &(addr, size) in mem_rsv {
+            mem_rsvmap.extend_from_slice(&addr.to_be_bytes());
+            mem_rsvmap.extend_from_slice(&size.to_be_bytes());
+        }
+        mem_rsvmap.extend_from_slice(&0u64.to_be_bytes());
+        mem_rsvmap.extend_from_slice(&0u64.to_be_bytes());
+
+        let off_mem_rsvmap = size_of::<FdtHeader>() as u32;
+        let off_dt_struct = off_mem_rsvmap + mem_rsvmap.len() as u32;
+        let off_dt_strings = off_dt_struct + self.struct_block.len() as u32;
+        let totalsize = off_dt_strings + self.strings.len() as u32;
+
+        let header = FdtHeader {
+            magic: FDT_MAGIC.into(),
+            totalsize: totalsize.into(),
+            off_dt_struct: off_dt_struct.into(),
+            off_dt_strings: off_dt_strings.into(),
+            off_mem_rsvmap: off_mem_rsvmap.into(),
+            version: FDT_VERSION.into(),
+            last_comp_version: FDT_LAST_COMP_VERSION.into(),
+            boot_cpuid_phys: boot_cpuid_phys.into(),
+            size_dt_strings: (self.strings.len() as u32).into(),
+            size_dt_struct: (self.struct_block.len() as u32).into(),
+        };
+
+        let mut blob = Vec::with_capacity(totalsize as usize);
+        blob.extend_from_slice(header.as_bytes());
+        blob.extend_from_slice(&mem_rsvmap);
+        blob.extend_from_slice(&self.struct_block);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
@@ -0,0 +1,264 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Direct-boot loader for raw ELF64 payloads, with an optional Xen/PVH entry.
+//!
+//! This is the path taken for anything that is not a Linux bzImage: firmware
+//! images, unikernels, or a Linux kernel built without the bzImage wrapper.
+//! Program headers are copied verbatim to their physical load address and
+//! execution starts at `e_entry`, unless the image carries a
+//! `XEN_ELFNOTE_PHYS32_ENTRY` note, in which case an `hvm_start_info` struct
+//! is built in guest memory and the PVH entry point is returned with
+//! [`BootProto::Pvh`]. The x86_64 board does not enter a PVH payload: its
+//! `init_boot_vcpu` returns `PvhNotSupported` for that boot protocol.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::mem::size_of;
+
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+use crate::mem::{MemRegionEntry, RamBus};
+
+use super::{BootProto, Error, InitState};
+
+const EI_NIDENT: usize = 16;
+const ELFMAG: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ET_EXEC: u16 = 2;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
+
+#[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes)]
+#[repr(C)]
+struct Elf64Ehdr {
+    e_ident: [u8; EI_NIDENT],
+    e_type: u16,
+    e_machine: u16,
+    e_version: u32,
+    e_entry: u64,
+    e_phoff: u64,
+    e_shoff: u64,
+    e_flags: u32,
+    e_ehsize: u16,
+    e_phentsize: u16,
+    e_phnum: u16,
+    e_shentsize: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+#[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes)]
+#[repr(C)]
+struct Elf64Phdr {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    p_vaddr: u64,
+    p_paddr: u64,
+    p_filesz: u64,
+    p_memsz: u64,
+    p_align: u64,
+}
+
+#[derive(Debug, Clone, FromBytes, FromZeroes, AsBytes)]
+#[repr(C)]
+struct Elf64Nhdr {
+    n_namesz: u32,
+    n_descsz: u32,
+    n_type: u32,
+}
+
+/// `xen/include/public/hvm/start_info.h`.
+const HVM_START_MAGIC_VALUE: u32 = 0x336ec578;
+
+#[derive(Debug, Clone, Default, FromBytes, FromZeroes, AsBytes)]
+#[repr(C)]
+struct HvmStartInfo {
+    magic: u32,
+    version: u32,
+    flags: u32,
+    nr_modules: u32,
+    modlist_paddr: u64,
+    cmdline_paddr: u64,
+    rsdp_paddr: u64,
+    memmap_paddr: u64,
+    memmap_entries: u32,
+    reserved: u32,
+}
+
+#[derive(Debug, Clone, Default, FromBytes, FromZeroes, AsBytes)]
+#[repr(C)]
+struct HvmModlistEntry {
+    paddr: u64,
+    size: u64,
+    cmdline_paddr: u64,
+    reserved: u64,
+}
+
+fn read_at(file: &mut File, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![0u8; len];
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_ehdr(file: &mut File) -> Result<Elf64Ehdr, Error> {
+    let bytes = read_at(file, 0, size_of::<Elf64Ehdr>())?;
+    let ehdr = Elf64Ehdr::read_from(&bytes[..]).ok_or(Error::InvalidElf)?;
+    if ehdr.e_ident[0..4] != ELFMAG || ehdr.e_ident[4] != ELFCLASS64 || ehdr.e_type != ET_EXEC {
+        return Err(Error::InvalidElf);
+    }
+    Ok(ehdr)
+}
+
+fn read_phdrs(file: &mut File, ehdr: &Elf64Ehdr) -> Result<Vec<Elf64Phdr>, Error> {
+    let mut phdrs = Vec::with_capacity(ehdr.e_phnum as usize);
+    for index in 0..ehdr.e_phnum {
+        let offset = ehdr.e_phoff + index as u64 * ehdr.e_phentsize as u64;
+        let bytes = read_at(file, offset, size_of::<Elf64Phdr>())?;
+        phdrs.push(Elf64Phdr::read_from(&bytes[..]).ok_or(Error::InvalidElf)?);
+    }
+    Ok(phdrs)
+}
+
+/// Scans `PT_NOTE` segments for `XEN_ELFNOTE_PHYS32_ENTRY` and returns the
+/// 32-bit PVH entry point, if present.
+fn find_pvh_entry(file: &mut File, phdrs: &[Elf64Phdr]) -> Result<Option<u32>, Error> {
+    for phdr in phdrs {
+        if phdr.p_type != PT_NOTE {
+            continue;
+        }
+        let mut offset = phdr.p_offset;
+        let end = phdr.p_offset + phdr.p_filesz;
+        while offset + size_of::<Elf64Nhdr>() as u64 <= end {
+            let nhdr_bytes = read_at(file, offset, size_of::<Elf64Nhdr>())?;
+            let nhdr = Elf64Nhdr::read_from(&nhdr_bytes[..]).ok_or(Error::InvalidElf)?;
+            let name_off = offset + size_of::<Elf64Nhdr>() as u64;
+            let name_len = nhdr.n_namesz as usize;
+            let name = read_at(file, name_off, name_len)?;
+            let desc_off = align_up(name_off + name_len as u64, 4);
+            if nhdr.n_type == XEN_ELFNOTE_PHYS32_ENTRY && name.starts_with(b"Xen") {
+                let desc = read_at(file, desc_off, size_of::<u32>())?;
+                return Ok(Some(u32::read_from(&desc[..]).ok_or(Error::InvalidElf)?));
+            }
+            offset = align_up(desc_off + nhdr.n_descsz as u64, 4);
+        }
+    }
+    Ok(None)
+}
+
+fn align_up(val: u64, align: u64) -> u64 {
+    (val + align - 1) & !(align - 1)
+}
+
+fn build_hvm_start_info(
+    ram_bus: &RamBus,
+    start_info_addr: u64,
+    cmd_line: Option<&str>,
+    module: Option<(u64, u64)>,
+) -> Result<(), Error> {
+    let mut info = HvmStartInfo {
+        magic: HVM_START_MAGIC_VALUE,
+        version: 1,
+        ..Default::default()
+    };
+    let mut next_free = start_info_addr + size_of::<HvmStartInfo>() as u64;
+
+    if let Some(cmd_line) = cmd_line {
+        let mut bytes = cmd_line.as_bytes().to_vec();
+        bytes.push(0);
+        ram_bus.write_range(next_free as usize, bytes.len(), &bytes)?;
+        info.cmdline_paddr = next_free;
+        next_free = align_up(next_free + bytes.len() as u64, 8);
+    }
+
+    if let Some((mod_paddr, mod_size)) = module {
+        let modlist = HvmModlistEntry {
+            paddr: mod_paddr,
+            size: mod_size,
+            ..Default::default()
+        };
+        let bytes = modlist.as_bytes();
+        ram_bus.write_range(next_free as usize, bytes.len(), bytes)?;
+        info.nr_modules = 1;
+        info.modlist_paddr = next_free;
+    }
+
+    let bytes = info.as_bytes();
+    ram_bus.write_range(start_info_addr as usize, bytes.len(), bytes)?;
+    Ok(())
+}
+
+/// Loads a raw ELF64 payload, copying each `PT_LOAD` segment to its
+/// `p_paddr` and returning the resulting entry point and boot protocol.
+pub fn load(
+    ram_bus: &RamBus,
+    _mem_regions: &[MemRegionEntry],
+    executable: &File,
+    cmd_line: Option<&str>,
+    initramfs: Option<&File>,
+) -> Result<InitState, Error> {
+    let mut file = executable.try_clone()?;
+    let ehdr = read_ehdr(&mut file)?;
+    let phdrs = read_phdrs(&mut file, &ehdr)?;
+
+    let mut highest_load_end = 0u64;
+    for phdr in &phdrs {
+        if phdr.p_type != PT_LOAD || phdr.p_filesz == 0 {
+            continue;
+        }
+        let data = read_at(&mut file, phdr.p_offset, phdr.p_filesz as usize)?;
+        ram_bus.write_range(phdr.p_paddr as usize, data.len(), &data)?;
+        highest_load_end = highest_load_end.max(phdr.p_paddr + phdr.p_memsz);
+    }
+
+    let pvh_entry = find_pvh_entry(&mut file, &phdrs)?;
+
+    let module = if let Some(initramfs) = initramfs {
+        let mut initramfs = initramfs.try_clone()?;
+        let len = initramfs.metadata()?.len() as usize;
+        let mut buf = vec![0u8; len];
+        initramfs.read_exact(&mut buf)?;
+        let addr = align_up(highest_load_end, 0x1000);
+        ram_bus.write_range(addr as usize, buf.len(), &buf)?;
+        Some((addr, len as u64))
+    } else {
+        None
+    };
+    let initramfs_span = module.map(|(addr, size)| (addr, addr + size));
+
+    if let Some(pvh_entry) = pvh_entry {
+        let start_info_addr = align_up(
+            module
+                .map(|(addr, size)| addr + size)
+                .unwrap_or(highest_load_end),
+            8,
+        );
+        build_hvm_start_info(ram_bus, start_info_addr, cmd_line, module)?;
+        Ok(InitState {
+            entry_point: pvh_entry as u64,
+            boot_proto: BootProto::Pvh { start_info_addr },
+            initramfs: initramfs_span,
+        })
+    } else {
+        Ok(InitState {
+            entry_point: ehdr.e_entry,
+            boot_proto: BootProto::Elf,
+            initramfs: initramfs_span,
+        })
+    }
+}
@@ -0,0 +1,91 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::File;
+
+use thiserror::Error;
+
+pub mod elf;
+pub mod linux;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("memory: {0}")]
+    Memory(#[from] crate::mem::Error),
+
+    #[error("not a valid ELF64 file")]
+    InvalidElf,
+
+    #[error("ELF entry point {0:#x} is not mapped by any PT_LOAD segment")]
+    EntryNotMapped(u64),
+
+    #[error("payload is too large for the guest memory layout")]
+    PayloadTooLarge,
+}
+
+/// Which boot convention the guest entry point expects.
+///
+/// `init_boot_vcpu` uses this to decide what the boot vCPU's registers
+/// should look like when the guest is first run.
+#[derive(Debug, Clone, Copy)]
+pub enum BootProto {
+    /// Linux x86 boot protocol: `%rsi` points at `boot_params`.
+    Linux { boot_params_addr: u64 },
+    /// The 64-bit System V ABI entry point of a bare ELF payload.
+    Elf,
+    /// The x86/PVH boot protocol: `%rbx` points at `hvm_start_info`.
+    Pvh { start_info_addr: u64 },
+}
+
+/// The result of loading a payload into guest memory: where to start
+/// executing, which boot protocol is in effect there, and where any
+/// initramfs ended up (guest-physical `(start, end)`), if one was loaded.
+#[derive(Debug, Clone, Copy)]
+pub struct InitState {
+    pub entry_point: u64,
+    pub boot_proto: BootProto,
+    pub initramfs: Option<(u64, u64)>,
+}
+
+impl Default for InitState {
+    fn default() -> Self {
+        InitState {
+            entry_point: 0,
+            boot_proto: BootProto::Elf,
+            initramfs: None,
+        }
+    }
+}
+
+/// How to interpret `Payload::executable`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecType {
+    /// A Linux bzImage, loaded with the Linux/x86 boot protocol.
+    Linux,
+    /// A raw ELF64 image, loaded at its `p_paddr`s and entered at `e_entry`.
+    /// If an `XEN_ELFNOTE_PHYS32_ENTRY` note is present, the loader reports
+    /// the PVH entry point with [`BootProto::Pvh`] instead, and the x86_64
+    /// board refuses to boot it with `PvhNotSupported`.
+    Elf,
+}
+
+pub struct Payload {
+    pub executable: File,
+    pub exec_type: ExecType,
+    pub cmd_line: Option<String>,
+    pub initramfs: Option<File>,
+}
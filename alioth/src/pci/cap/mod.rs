@@ -0,0 +1,119 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The PCI capability linked list that lives past `0x40` in an emulated
+//! device's config space. Each entry is the standard `{cap_id, next}` pair
+//! followed by capability-specific bytes; [`PciCapList`] owns the
+//! `{cap_id, next}` framing itself and forwards everything past it to the
+//! capability's own [`Mmio`] implementation.
+
+use std::mem::size_of;
+
+use crate::mem::{self, emulated::Mmio};
+use crate::pci::config::DeviceHeader;
+
+pub mod msix;
+
+const CAP_HEADER_LEN: usize = 2;
+
+struct CapEntry {
+    id: u8,
+    offset: usize,
+    next: u8,
+    data: Box<dyn Mmio + Send + Sync>,
+}
+
+/// The capability linked list attached to an [`crate::pci::config::EmulatedConfig`].
+#[derive(Default)]
+pub struct PciCapList {
+    caps: Vec<CapEntry>,
+    total_len: usize,
+}
+
+impl PciCapList {
+    /// Builds the list from `(cap_id, capability)` pairs, in the order they
+    /// should appear on the chain, and lays out their config-space offsets
+    /// and `next` pointers.
+    pub fn new(caps: Vec<(u8, Box<dyn Mmio + Send + Sync>)>) -> Self {
+        let mut offset = size_of::<DeviceHeader>();
+        let mut entries = Vec::with_capacity(caps.len());
+        for (id, data) in caps {
+            let len = CAP_HEADER_LEN + data.size();
+            entries.push(CapEntry {
+                id,
+                offset,
+                next: 0,
+                data,
+            });
+            offset += len;
+        }
+        let count = entries.len();
+        let next_offsets: Vec<usize> = entries.iter().skip(1).map(|entry| entry.offset).collect();
+        for (index, entry) in entries.iter_mut().enumerate() {
+            entry.next = if index + 1 < count {
+                next_offsets[index] as u8
+            } else {
+                0
+            };
+        }
+        let total_len = offset - size_of::<DeviceHeader>();
+        PciCapList {
+            caps: entries,
+            total_len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.caps.is_empty()
+    }
+
+    fn find(&self, offset: usize) -> Option<&CapEntry> {
+        self.caps.iter().find(|entry| {
+            offset >= entry.offset && offset < entry.offset + CAP_HEADER_LEN + entry.data.size()
+        })
+    }
+}
+
+impl Mmio for PciCapList {
+    fn size(&self) -> usize {
+        self.total_len
+    }
+
+    fn read(&self, offset: usize, size: u8) -> mem::Result<u64> {
+        let Some(entry) = self.find(offset) else {
+            return Ok(0);
+        };
+        let rel = offset - entry.offset;
+        if rel == 0 {
+            Ok(entry.id as u64)
+        } else if rel == 1 {
+            Ok(entry.next as u64)
+        } else {
+            entry.data.read(rel - CAP_HEADER_LEN, size)
+        }
+    }
+
+    fn write(&self, offset: usize, size: u8, val: u64) -> mem::Result<()> {
+        let Some(entry) = self.find(offset) else {
+            return Ok(());
+        };
+        let rel = offset - entry.offset;
+        if rel < CAP_HEADER_LEN {
+            // `cap_id`/`next` are read-only.
+            Ok(())
+        } else {
+            entry.data.write(rel - CAP_HEADER_LEN, size, val)
+        }
+    }
+}
@@ -0,0 +1,234 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! MSI-X capability emulation (PCI spec 3.0 section 6.8.2): the capability
+//! structure itself plus the table and PBA it points at, which live in one
+//! of the device's BARs.
+
+use parking_lot::RwLock;
+
+use crate::assign_bits;
+use crate::mem::{self, emulated::Mmio};
+
+const MSG_CTL_ENABLE: u16 = 1 << 15;
+const MSG_CTL_FUNCTION_MASK: u16 = 1 << 14;
+const MSG_CTL_WRITABLE: u16 = MSG_CTL_ENABLE | MSG_CTL_FUNCTION_MASK;
+const MSG_CTL_TABLE_SIZE_MASK: u16 = 0x7ff;
+
+const ENTRY_SIZE: usize = 16;
+const VECTOR_CTL_MASKED: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct TableEntry {
+    addr_lo: u32,
+    addr_hi: u32,
+    data: u32,
+    vector_control: u32,
+}
+
+/// The MSI-X table, memory-mapped at `table_offset` within its BAR.
+#[derive(Debug)]
+pub struct MsiXTable {
+    entries: RwLock<Vec<TableEntry>>,
+}
+
+impl MsiXTable {
+    fn new(num_vectors: u16) -> Self {
+        MsiXTable {
+            entries: RwLock::new(vec![TableEntry::default(); num_vectors as usize]),
+        }
+    }
+}
+
+impl Mmio for MsiXTable {
+    fn size(&self) -> usize {
+        self.entries.read().len() * ENTRY_SIZE
+    }
+
+    fn read(&self, offset: usize, size: u8) -> mem::Result<u64> {
+        let entries = self.entries.read();
+        let index = offset / ENTRY_SIZE;
+        let Some(entry) = entries.get(index) else {
+            return Ok(0);
+        };
+        let field = match (offset % ENTRY_SIZE) / 4 {
+            0 => entry.addr_lo,
+            1 => entry.addr_hi,
+            2 => entry.data,
+            _ => entry.vector_control,
+        };
+        Ok(match size {
+            1 => (field & 0xff) as u64,
+            2 => (field & 0xffff) as u64,
+            _ => field as u64,
+        })
+    }
+
+    fn write(&self, offset: usize, _size: u8, val: u64) -> mem::Result<()> {
+        let mut entries = self.entries.write();
+        let index = offset / ENTRY_SIZE;
+        let Some(entry) = entries.get_mut(index) else {
+            return Ok(());
+        };
+        match (offset % ENTRY_SIZE) / 4 {
+            0 => entry.addr_lo = val as u32,
+            1 => entry.addr_hi = val as u32,
+            2 => entry.data = val as u32,
+            _ => entry.vector_control = val as u32,
+        }
+        Ok(())
+    }
+}
+
+/// The Pending Bit Array, memory-mapped at `pba_offset` within its BAR.
+#[derive(Debug)]
+pub struct MsiXPba {
+    bits: RwLock<Vec<u64>>,
+}
+
+impl MsiXPba {
+    fn new(num_vectors: u16) -> Self {
+        let qwords = (num_vectors as usize).div_ceil(64);
+        MsiXPba {
+            bits: RwLock::new(vec![0; qwords.max(1)]),
+        }
+    }
+
+    fn set(&self, vector: u16) {
+        let mut bits = self.bits.write();
+        let word = vector as usize / 64;
+        if let Some(entry) = bits.get_mut(word) {
+            *entry |= 1 << (vector % 64);
+        }
+    }
+
+    fn clear(&self, vector: u16) {
+        let mut bits = self.bits.write();
+        let word = vector as usize / 64;
+        if let Some(entry) = bits.get_mut(word) {
+            *entry &= !(1 << (vector % 64));
+        }
+    }
+}
+
+impl Mmio for MsiXPba {
+    fn size(&self) -> usize {
+        self.bits.read().len() * 8
+    }
+
+    fn read(&self, offset: usize, _size: u8) -> mem::Result<u64> {
+        Ok(self.bits.read().get(offset / 8).copied().unwrap_or(0))
+    }
+
+    fn write(&self, _offset: usize, _size: u8, _val: u64) -> mem::Result<()> {
+        // The PBA is read-only from the guest's point of view.
+        Ok(())
+    }
+}
+
+/// The 10-byte MSI-X capability payload (past the standard `{cap_id, next}`
+/// header): message control, then the BAR-indirect table and PBA offsets.
+#[derive(Debug)]
+pub struct MsiXCap {
+    message_control: RwLock<u16>,
+    table_offset_bir: u32,
+    pba_offset_bir: u32,
+    pub table: MsiXTable,
+    pub pba: MsiXPba,
+}
+
+impl MsiXCap {
+    /// `table_bar`/`pba_bar` are the indices (0-5) of the BARs the table and
+    /// PBA live in; `table_bar_offset`/`pba_bar_offset` are their byte
+    /// offsets within those BARs.
+    pub fn new(
+        num_vectors: u16,
+        table_bar: u8,
+        table_bar_offset: u32,
+        pba_bar: u8,
+        pba_bar_offset: u32,
+    ) -> Self {
+        assert!(num_vectors >= 1, "MSI-X requires at least one vector");
+        MsiXCap {
+            message_control: RwLock::new((num_vectors - 1) & MSG_CTL_TABLE_SIZE_MASK),
+            table_offset_bir: (table_bar_offset & !0x7) | table_bar as u32,
+            pba_offset_bir: (pba_bar_offset & !0x7) | pba_bar as u32,
+            table: MsiXTable::new(num_vectors),
+            pba: MsiXPba::new(num_vectors),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        *self.message_control.read() & MSG_CTL_ENABLE != 0
+    }
+
+    fn function_masked(&self) -> bool {
+        *self.message_control.read() & MSG_CTL_FUNCTION_MASK != 0
+    }
+
+    /// Sends vector `vector`'s message-signaled interrupt through `notify`.
+    /// Does nothing while MSI-X is disabled. If the whole function or the
+    /// vector itself is masked, sets the vector's PBA bit instead of sending;
+    /// unmasking does not send it, and the bit is cleared by the next
+    /// `send_msi` call that delivers the vector.
+    pub fn send_msi(&self, vector: u16, notify: impl FnOnce(u64, u32)) {
+        if !self.enabled() {
+            return;
+        }
+        let entries = self.table.entries.read();
+        let Some(entry) = entries.get(vector as usize) else {
+            return;
+        };
+        let masked = self.function_masked() || entry.vector_control & VECTOR_CTL_MASKED != 0;
+        let addr = (entry.addr_lo as u64) | ((entry.addr_hi as u64) << 32);
+        let data = entry.data;
+        drop(entries);
+        if masked {
+            self.pba.set(vector);
+        } else {
+            self.pba.clear(vector);
+            notify(addr, data);
+        }
+    }
+}
+
+impl Mmio for MsiXCap {
+    fn size(&self) -> usize {
+        10
+    }
+
+    fn read(&self, offset: usize, size: u8) -> mem::Result<u64> {
+        match offset {
+            0 => {
+                let val = *self.message_control.read();
+                Ok(match size {
+                    1 => (val & 0xff) as u64,
+                    _ => val as u64,
+                })
+            }
+            2..=5 => Ok(self.table_offset_bir as u64),
+            6..=9 => Ok(self.pba_offset_bir as u64),
+            _ => Ok(0),
+        }
+    }
+
+    fn write(&self, offset: usize, _size: u8, val: u64) -> mem::Result<()> {
+        if offset == 0 {
+            let mut message_control = self.message_control.write();
+            assign_bits!(*message_control, val as u16, MSG_CTL_WRITABLE);
+        }
+        // The table/PBA offset-BIR fields are read-only.
+        Ok(())
+    }
+}